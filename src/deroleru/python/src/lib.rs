@@ -48,7 +48,11 @@ fn process_data_py(_: cpython::Python,
         similarity: similarity,
         max_nb_peaks: max_nb_peaks,
         percent_std: percent_std,
-        flat: true,
+        format: deroleru::OutputFormat::Flat,
+        mode: deroleru::PeakMode::LocalMax,
+        lag: 0,
+        threshold: 0.0,
+        influence: 0.0,
     };
 
     // Build a Data structure
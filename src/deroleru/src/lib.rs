@@ -28,6 +28,32 @@ pub struct Data {
 }
 
 
+#[derive(Debug, Clone, Copy)]
+#[derive(RustcEncodable)]
+pub enum OutputFormat {
+    // One line per document: the five LocalMax parameters, then the document's
+    // ASes and its leaks, each comma separated (the z-score settings are not printed)
+    Flat,
+    // One "AS leak_index" line per detected leak
+    Pairs,
+    // One JSON object per detected leak, with the scoring metadata behind it
+    Json,
+}
+
+
+#[derive(Debug, Clone, Copy)]
+#[derive(RustcEncodable)]
+pub enum PeakMode {
+    // Strict local maxima, filtered by is_close_to_abs_max() and check_std_variation()
+    LocalMax,
+    // Sliding-window robust z-score thresholding, see find_maxes_z_score().
+    // Note: a spike immediately following a perfectly flat `lag`-window (std
+    // == 0, e.g. a conflicts series sitting at a constant value) is never
+    // detected, since a zero std is treated as no signal.
+    ZScore,
+}
+
+
 #[derive(Debug)]
 #[derive(RustcEncodable)]
 pub struct Parameters {
@@ -36,7 +62,25 @@ pub struct Parameters {
     pub similarity: f32,
     pub max_nb_peaks: u32,
     pub percent_std: f32,
-    pub flat: bool,
+    pub format: OutputFormat,
+    pub mode: PeakMode,
+    pub lag: u32,
+    pub threshold: f32,
+    pub influence: f32,
+}
+
+
+#[derive(Debug)]
+#[derive(RustcEncodable)]
+pub struct LeakResult {
+    pub asn: u32,
+    pub index: u32,
+    pub prefixes_peak: u32,
+    pub conflicts_peak: u32,
+    pub prefixes_abs_max: u32,
+    pub conflicts_abs_max: u32,
+    pub prefixes_similarity_ratio: f32,
+    pub conflicts_similarity_ratio: f32,
 }
 
 
@@ -213,8 +257,58 @@ fn check_std_variation(values: &Vec<u32>, big_maxes: &Vec<u32>, params: &Paramet
 }
 
 
+fn window_average(window: &[f32]) -> f32 {
+    window.iter().sum::<f32>() / (window.len() as f32)
+}
+
+
+fn window_std(window: &[f32], average: f32) -> f32 {
+    let var = window
+        .iter()
+        .map(|v| pow(*v - average, 2))
+        .sum::<f32>() / (window.len() as f32);
+    var.sqrt()
+}
+
+
+// Robust z-score peak detector, usable on trending/non-stationary series
+fn find_maxes_z_score(values: &Vec<u32>, params: &Parameters) -> Option<Vec<u32>> {
+
+    let lag = params.lag as usize;
+
+    if values.len() <= lag {
+        return None;
+    }
+
+    // Filtered copy of the series: peaks are blended back in according to
+    // `influence`, so a single dominant spike doesn't distort the baseline
+    let mut filtered: Vec<f32> = values.iter().map(|v| *v as f32).collect();
+
+    let mut peaks = Vec::new();
+
+    for i in lag..values.len() {
+        let average = window_average(&filtered[i - lag..i]);
+        let std = window_std(&filtered[i - lag..i], average);
+
+        if std > 0.0 && (values[i] as f32 - average).abs() > params.threshold * std {
+            peaks.push(i as u32);
+            filtered[i] = params.influence * (values[i] as f32) +
+                          (1.0 - params.influence) * filtered[i - 1];
+        } else {
+            filtered[i] = values[i] as f32;
+        }
+    }
+
+    if peaks.is_empty() { None } else { Some(peaks) }
+}
+
+
 fn find_maxes(values: &Vec<u32>, params: &Parameters, peak_min_value: u32) -> Option<Vec<u32>> {
 
+    if let PeakMode::ZScore = params.mode {
+        return find_maxes_z_score(values, params);
+    }
+
     // Perform pre-computations
     let (local_maxes, variations, absolute_max) = pre_computations(values);
 
@@ -262,43 +356,92 @@ fn find_maxes_conflicts(values: &Vec<u32>, params: &Parameters) -> Option<Vec<u3
 }
 
 
-fn print_leaks(handle: &mut io::StdoutLock,
-               ases: &Vec<u32>,
-               leaks: &Vec<u32>,
-               params: &Parameters)
-               -> () {
+fn abs_maxima(doc: &Data) -> (u32, u32) {
+    // Compute each series' absolute maximum once per document, so per-leak
+    // JSON rendering doesn't re-scan the whole series for every (asn, leak) pair
+
+    let (_, _, prefixes_abs_max) = pre_computations(&doc.prefixes);
+    let (_, _, conflicts_abs_max) = pre_computations(&doc.conflicts);
+
+    (prefixes_abs_max, conflicts_abs_max)
+}
+
+
+fn leak_result(doc: &Data,
+               asn: u32,
+               index: u32,
+               prefixes_abs_max: u32,
+               conflicts_abs_max: u32)
+               -> LeakResult {
+    // Build the scoring metadata behind a single detected leak
+
+    let prefixes_peak = doc.prefixes[index as usize];
+    let conflicts_peak = doc.conflicts[index as usize];
+
+    LeakResult {
+        asn: asn,
+        index: index,
+        prefixes_peak: prefixes_peak,
+        conflicts_peak: conflicts_peak,
+        prefixes_abs_max: prefixes_abs_max,
+        conflicts_abs_max: conflicts_abs_max,
+        prefixes_similarity_ratio: prefixes_peak as f32 / prefixes_abs_max as f32,
+        conflicts_similarity_ratio: conflicts_peak as f32 / conflicts_abs_max as f32,
+    }
+}
+
+
+fn print_leaks<W: Write>(handle: &mut W,
+                         doc: &Data,
+                         leaks: &Vec<u32>,
+                         params: &Parameters,
+                         prefixes_abs_max: u32,
+                         conflicts_abs_max: u32)
+                         -> () {
     // Display leaks according to the command line argument
 
-    if params.flat {
-        write!(handle,
-               "{} {} {} {} {} ",
-               params.prefixes_peak_min_value,
-               params.conflicts_peak_min_value,
-               params.similarity,
-               params.max_nb_peaks,
-               params.percent_std)
-                .unwrap();
-
-        write!(handle,
-               "{} ",
-               ases.iter()
-                   .map(|asn| asn.to_string())
-                   .collect::<Vec<String>>()
-                   .join(","))
-                .unwrap();
-
-        writeln!(handle,
-                 "{}",
-                 leaks
-                     .iter()
-                     .map(|asn| asn.to_string())
-                     .collect::<Vec<String>>()
-                     .join(","))
-                .unwrap();
-    } else {
-        for asn in ases {
-            for leak in leaks {
-                writeln!(handle, "{} {}", asn, leak).unwrap();
+    match params.format {
+        OutputFormat::Flat => {
+            write!(handle,
+                   "{} {} {} {} {} ",
+                   params.prefixes_peak_min_value,
+                   params.conflicts_peak_min_value,
+                   params.similarity,
+                   params.max_nb_peaks,
+                   params.percent_std)
+                    .unwrap();
+
+            write!(handle,
+                   "{} ",
+                   doc.ases
+                       .iter()
+                       .map(|asn| asn.to_string())
+                       .collect::<Vec<String>>()
+                       .join(","))
+                    .unwrap();
+
+            writeln!(handle,
+                     "{}",
+                     leaks
+                         .iter()
+                         .map(|asn| asn.to_string())
+                         .collect::<Vec<String>>()
+                         .join(","))
+                    .unwrap();
+        }
+        OutputFormat::Pairs => {
+            for asn in &doc.ases {
+                for leak in leaks {
+                    writeln!(handle, "{} {}", asn, leak).unwrap();
+                }
+            }
+        }
+        OutputFormat::Json => {
+            for asn in &doc.ases {
+                for leak in leaks {
+                    let result = leak_result(doc, *asn, *leak, prefixes_abs_max, conflicts_abs_max);
+                    writeln!(handle, "{}", json::encode(&result).unwrap()).unwrap();
+                }
             }
         }
     }
@@ -337,38 +480,73 @@ fn identify_leaks(prefixes_indexes: &mut Vec<u32>,
 }
 
 
-pub fn read_data(filename: &str) -> Result<Vec<Data>, ReadError> {
-    // Load data from a JSON file
+// Iterator over the documents of a NDJSON file, decoding one line at a time so
+// callers never need to hold the whole dataset in memory.
+pub struct DataIter<R: BufRead> {
+    reader: R,
+    line_num: u32,
+}
 
-    let mut data = Vec::new();
+impl<R: BufRead> DataIter<R> {
+    fn from_reader(reader: R) -> DataIter<R> {
+        DataIter {
+            reader: reader,
+            line_num: 1,
+        }
+    }
+}
 
-    let file = try!(File::open(filename));
-    let mut reader = BufReader::new(file);
+impl<R: BufRead> Iterator for DataIter<R> {
+    type Item = Result<Data, ReadError>;
 
-    let mut line_num = 1;
-    loop {
-        // Parse a JSON document and convert it to a Rust structure
+    fn next(&mut self) -> Option<Self::Item> {
         let mut line = String::new();
-        match reader.read_line(&mut line) {
-            Err(e) => return Err(ReadError::ReadLineError(line_num, e)),
-            Ok(len) if len == 0 => break,
+        match self.reader.read_line(&mut line) {
+            Err(e) => return Some(Err(ReadError::ReadLineError(self.line_num, e))),
+            Ok(0) => return None,
             Ok(_) => (),
         }
 
-        let doc: Data =
-            try!(json::decode(&line).map_err(|e| ReadError::DataFormatError(line_num, e)));
-        data.push(doc);
+        // A final line with no trailing newline that fails to decode is taken as
+        // a file cut off mid-write; one that decodes is accepted as a document.
+        let truncated = !line.ends_with('\n');
 
-        line_num += 1;
+        let line_num = self.line_num;
+        self.line_num += 1;
+
+        Some(json::decode(&line).map_err(|e| if truncated {
+                                              ReadError::UnexpectedEof(line_num,
+                                                                       io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                                                      "truncated final line"))
+                                          } else {
+                                              ReadError::DataFormatError(line_num, e)
+                                          }))
     }
+}
 
-    Ok(data)
 
+pub fn read_data_iter(filename: &str) -> Result<DataIter<BufReader<File>>, ReadError> {
+    // Open a JSON file for lazy, line-by-line ingestion
+
+    let file = try!(File::open(filename));
+    Ok(DataIter::from_reader(BufReader::new(file)))
+}
+
+
+pub fn read_data(filename: &str) -> Result<Vec<Data>, ReadError> {
+    // Load data from a JSON file
+
+    try!(read_data_iter(filename)).collect()
 }
 
-pub fn process_data(data: &Vec<Data>, params: &Parameters) -> () {
+pub fn process_data<I>(data: I, params: &Parameters) -> Result<(), ReadError>
+    where I: Iterator<Item = Result<Data, ReadError>>
+{
+    // Stream documents one at a time, printing leaks as soon as they are found
+
+    for doc_result in data {
+        let doc = try!(doc_result);
 
-    for doc in data {
         #[cfg(feature = "debug")]
         println!("ases={:?}", doc.ases);
 
@@ -376,13 +554,50 @@ pub fn process_data(data: &Vec<Data>, params: &Parameters) -> () {
         match process_doc(&doc, &params) {
             None => (),
             Some(leaks) => {
+                let (prefixes_abs_max, conflicts_abs_max) = abs_maxima(&doc);
                 let stdout = io::stdout();
                 let mut handle = stdout.lock();
-                print_leaks(&mut handle, &doc.ases, &leaks, &params);
+                print_leaks(&mut handle,
+                            &doc,
+                            &leaks,
+                            &params,
+                            prefixes_abs_max,
+                            conflicts_abs_max);
             }
         }
 
     }
+
+    Ok(())
+}
+
+
+pub fn render_doc(doc: &Data, params_list: &Vec<Parameters>) -> Vec<u8> {
+    // Run every parameter set against a single document and render its leaks
+    // to a buffer instead of stdout, so a worker thread can hand back a
+    // self-contained chunk of output for the caller to print in order
+
+    let mut buffer = Vec::new();
+    let (prefixes_abs_max, conflicts_abs_max) = abs_maxima(doc);
+
+    for params in params_list {
+        #[cfg(feature = "debug")]
+        println!("ases={:?}", doc.ases);
+
+        match process_doc(doc, params) {
+            None => (),
+            Some(leaks) => {
+                print_leaks(&mut buffer,
+                            doc,
+                            &leaks,
+                            params,
+                            prefixes_abs_max,
+                            conflicts_abs_max)
+            }
+        }
+    }
+
+    buffer
 }
 
 
@@ -430,6 +645,63 @@ fn test_get_integer_ko_1() {
 }
 
 
+#[test]
+fn test_find_maxes_z_score() {
+    // A mildly noisy baseline (so the window std isn't zero) with one sharp spike
+    let values = vec![10, 11, 10, 11, 10, 11, 10, 11, 10, 11, 100, 10, 11, 10, 11, 10];
+    let params = Parameters {
+        prefixes_peak_min_value: 0,
+        conflicts_peak_min_value: 0,
+        similarity: 0.0,
+        max_nb_peaks: 0,
+        percent_std: 0.0,
+        format: OutputFormat::Pairs,
+        mode: PeakMode::ZScore,
+        lag: 5,
+        threshold: 3.0,
+        influence: 0.0,
+    };
+
+    let peaks = find_maxes_z_score(&values, &params);
+    assert_eq!(peaks, Some(vec![10]));
+}
+
+#[test]
+fn test_find_maxes_z_score_too_short() {
+    let values = vec![1, 1, 1];
+    let params = Parameters {
+        prefixes_peak_min_value: 0,
+        conflicts_peak_min_value: 0,
+        similarity: 0.0,
+        max_nb_peaks: 0,
+        percent_std: 0.0,
+        format: OutputFormat::Pairs,
+        mode: PeakMode::ZScore,
+        lag: 5,
+        threshold: 3.0,
+        influence: 0.0,
+    };
+
+    assert_eq!(find_maxes_z_score(&values, &params), None);
+}
+
+#[test]
+fn test_data_iter_truncated_line() {
+    let cursor = io::Cursor::new(b"{\"ases\":[1],\"prefixes\":[0,1,0],\"conflicts\":[0,1,0]}\n{\"ases\":[2]" as &[u8]);
+    let mut iter = DataIter::from_reader(cursor);
+
+    let doc = iter.next().unwrap().unwrap();
+    assert_eq!(doc.ases, vec![1]);
+
+    match iter.next().unwrap() {
+        Err(ReadError::UnexpectedEof(lnum, _)) => assert_eq!(lnum, 2),
+        other => panic!("expected UnexpectedEof, got {:?}", other),
+    }
+
+    assert!(iter.next().is_none());
+}
+
+
 fn get_float(iter: &mut str::SplitWhitespace) -> Result<f32, ReadError> {
     iter.next()
         .ok_or(ReadError::ParameterFormatError)
@@ -437,18 +709,47 @@ fn get_float(iter: &mut str::SplitWhitespace) -> Result<f32, ReadError> {
 }
 
 
-fn parse_parameter(line: &String, arg_flat: bool) -> Result<Parameters, ReadError> {
+fn get_peak_mode(iter: &mut str::SplitWhitespace) -> Result<(PeakMode, u32, f32, f32), ReadError> {
+    // The z-score mode and its lag/threshold/influence settings are optional,
+    // trailing fields: a line without them keeps running in LocalMax mode
+
+    match iter.next() {
+        None => Ok((PeakMode::LocalMax, 0, 0.0, 0.0)),
+        Some("localmax") => Ok((PeakMode::LocalMax, 0, 0.0, 0.0)),
+        Some("zscore") => {
+            let lag = try!(get_integer(iter));
+            let threshold = try!(get_float(iter));
+            let influence = try!(get_float(iter));
+            Ok((PeakMode::ZScore, lag, threshold, influence))
+        }
+        Some(_) => Err(ReadError::ParameterFormatError),
+    }
+}
+
+
+fn parse_parameter(line: &String, arg_format: OutputFormat) -> Result<Parameters, ReadError> {
     // Parse a parameter from a string
 
     let mut iter = line.split_whitespace();
 
+    let prefixes_peak_min_value = try!(get_integer(&mut iter));
+    let conflicts_peak_min_value = try!(get_integer(&mut iter));
+    let max_nb_peaks = try!(get_integer(&mut iter));
+    let similarity = try!(get_float(&mut iter));
+    let percent_std = try!(get_float(&mut iter));
+    let (mode, lag, threshold, influence) = try!(get_peak_mode(&mut iter));
+
     Ok(Parameters {
-           prefixes_peak_min_value: try!(get_integer(&mut iter)),
-           conflicts_peak_min_value: try!(get_integer(&mut iter)),
-           max_nb_peaks: try!(get_integer(&mut iter)),
-           similarity: try!(get_float(&mut iter)),
-           percent_std: try!(get_float(&mut iter)),
-           flat: arg_flat,
+           prefixes_peak_min_value: prefixes_peak_min_value,
+           conflicts_peak_min_value: conflicts_peak_min_value,
+           max_nb_peaks: max_nb_peaks,
+           similarity: similarity,
+           percent_std: percent_std,
+           format: arg_format,
+           mode: mode,
+           lag: lag,
+           threshold: threshold,
+           influence: influence,
        })
 }
 
@@ -462,6 +763,7 @@ pub enum ReadError {
     IntegerConvertError(std::num::ParseIntError),
     FloatConvertError(std::num::ParseFloatError),
     DataFormatError(u32, json::DecoderError),
+    UnexpectedEof(u32, io::Error),
 }
 
 impl fmt::Display for ReadError {
@@ -480,6 +782,9 @@ impl fmt::Display for ReadError {
             ReadError::DataFormatError(lnum, ref err) => {
                 write!(f, "invalid data at line #{} !\n    -> {}", lnum, err)
             }
+            ReadError::UnexpectedEof(lnum, ref err) => {
+                write!(f, "truncated document at line #{} !\n    -> {}", lnum, err)
+            }
         }
     }
 }
@@ -504,7 +809,7 @@ impl From<io::Error> for ReadError {
 }
 
 
-pub fn read_parameters(filename: &str, arg_flat: bool) -> Result<Vec<Parameters>, ReadError> {
+pub fn read_parameters(filename: &str, arg_format: OutputFormat) -> Result<Vec<Parameters>, ReadError> {
     // Load parameters from a file
 
     // Open the file for reading
@@ -525,7 +830,7 @@ pub fn read_parameters(filename: &str, arg_flat: bool) -> Result<Vec<Parameters>
         }
 
         // Parse a line as a parameter struct
-        data.push(try!(parse_parameter(&line, arg_flat)
+        data.push(try!(parse_parameter(&line, arg_format)
                        .map_err(|e| ReadError::ParameterLineError(line_num, Box::new(e)))));
 
         line_num += 1;
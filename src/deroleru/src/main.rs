@@ -5,6 +5,8 @@
 
 
 use std::io;
+use std::io::Write;
+use std::collections::BTreeMap;
 
 
 extern crate argparse;
@@ -24,9 +26,11 @@ fn main() {
 
     // Get command line arguments
     let mut arg_flat: bool = false;
+    let mut arg_format: String = String::new();
     let mut arg_params: String = String::new();
     let mut arg_progress: bool = false;
     let mut arg_filename: String = String::new();
+    let mut arg_threads: usize = 6;
 
     {
         let mut ap = ArgumentParser::new();
@@ -35,12 +39,22 @@ fn main() {
         ap.refer(&mut arg_flat)
             .add_option(&["--flat"], StoreTrue, "Dump flat results");
 
+        ap.refer(&mut arg_format)
+            .add_option(&["--format"],
+                        Store,
+                        "Output format: pairs (default), flat, or json");
+
         ap.refer(&mut arg_progress)
             .add_option(&["--progress"], StoreTrue, "Display a progress bar");
 
         ap.refer(&mut arg_params)
             .add_option(&["--params"], Store, "File that contains parameters");
 
+        ap.refer(&mut arg_threads)
+            .add_option(&["--threads"],
+                        Store,
+                        "Number of documents to process in parallel (default 6)");
+
         ap.refer(&mut arg_filename)
             .add_argument("filename", Store, "Filename to process")
             .required();
@@ -48,10 +62,19 @@ fn main() {
         ap.parse_args_or_exit();
     }
 
+    // --format takes precedence over the older --flat flag
+    let arg_format = match arg_format.as_str() {
+        "json" => deroleru::OutputFormat::Json,
+        "flat" => deroleru::OutputFormat::Flat,
+        "pairs" => deroleru::OutputFormat::Pairs,
+        _ if arg_flat => deroleru::OutputFormat::Flat,
+        _ => deroleru::OutputFormat::Pairs,
+    };
+
 
-    // Get data from file
-    let data = match deroleru::read_data(arg_filename.as_str()) {
-        Ok(d) => d,
+    // Open the data file for lazy, line-by-line ingestion
+    let data_iter = match deroleru::read_data_iter(arg_filename.as_str()) {
+        Ok(iter) => iter,
         Err(err) => {
             println!("Error while reading data: {}", err);
             return;
@@ -63,7 +86,7 @@ fn main() {
     let parameters = match arg_params.len() {
         // Parse parameters from file
         len if len > 0 => {
-            match deroleru::read_parameters(arg_params.as_str(), arg_flat) {
+            match deroleru::read_parameters(arg_params.as_str(), arg_format) {
                 Ok(p) => p,
                 Err(err) => {
                     println!("Error while reading parameters: {}", err);
@@ -79,20 +102,51 @@ fn main() {
                      similarity: 0.9,
                      max_nb_peaks: 2,
                      percent_std: 0.9,
-                     flat: arg_flat,
+                     format: arg_format,
+                     mode: deroleru::PeakMode::LocalMax,
+                     lag: 0,
+                     threshold: 0.0,
+                     influence: 0.0,
                  }]
         }
     };
 
 
-    // Parse data using parameters
-    let mut pb = ProgressBar::on(io::stderr(), parameters.len() as u64);
+    // Parallelize across documents rather than parameter sets, so a single
+    // parameter line no longer leaves the rest of the --threads pool idle.
+    // Each worker renders its leaks to a buffer instead of stdout directly;
+    // workers can finish out of order, so results are held in `pending` and
+    // flushed in the original document order before anything gets printed.
+    // The total document count isn't known up front when streaming, so the
+    // usual percentage/bar-fill display (which needs a total) would be
+    // meaningless; fall back to a plain running counter instead
+    let mut pb = ProgressBar::on(io::stderr(), 0);
+    pb.show_bar = false;
+    pb.show_percent = false;
+    pb.show_time_left = false;
+
+    let mut pending: BTreeMap<usize, Result<Vec<u8>, deroleru::ReadError>> = BTreeMap::new();
+    let mut next_index: usize = 0;
+
+    for (index, result) in data_iter
+            .enumerate()
+            .with_threads(arg_threads)
+            .map(move |(index, doc_result)| {
+                     (index, doc_result.map(|doc| deroleru::render_doc(&doc, &parameters)))
+                 }) {
+        pending.insert(index, result);
+
+        while let Some(result) = pending.remove(&next_index) {
+            match result {
+                Ok(buffer) => io::stdout().write_all(&buffer).unwrap(),
+                Err(err) => println!("Error while reading data: {}", err),
+            }
+
+            if arg_progress {
+                pb.inc();
+            }
 
-    for _ in parameters
-            .with_threads(6)
-            .map(move |params| deroleru::process_data(&data, &params)) {
-        if arg_progress {
-            pb.inc();
+            next_index += 1;
         }
     }
 